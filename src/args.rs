@@ -56,10 +56,101 @@ pub struct Args {
     )]
     pub wg_address: String,
 
+    #[arg(
+        long,
+        help = "fwmark to set on the WireGuard UDP socket (via SO_MARK), so its encrypted traffic isn't re-routed back through the TUN device's default route"
+    )]
+    pub fwmark: Option<u32>,
+
+    #[arg(
+        long = "preshared-key-file",
+        help = "path to a preshared key file for the primary --wg-* peer, for interop with peers that require one"
+    )]
+    pub preshared_key_file: Option<String>,
+
+    #[arg(
+        long = "persistent-keepalive",
+        help = "persistent keepalive interval in seconds for the primary --wg-* peer (disabled by default, matching upstream WireGuard)"
+    )]
+    pub persistent_keepalive: Option<u16>,
+
+    #[arg(
+        long,
+        help = "number of TUN queues / worker tasks used to encapsulate and decapsulate packets (defaults to the number of available cores)"
+    )]
+    pub threads: Option<usize>,
+
+    #[arg(
+        long = "uapi-socket",
+        help = "path to a Unix socket that speaks the WireGuard cross-platform UAPI protocol, for runtime peer management with `wg`/`wg show`"
+    )]
+    pub uapi_socket: Option<String>,
+
+    #[arg(
+        long = "peer",
+        value_parser = parse_peer,
+        help = "additional peer in format: pubkey,endpoint,allowed_ip[;allowed_ip...] (can be specified multiple times)"
+    )]
+    pub peers: Vec<PeerConfig>,
+
+    #[arg(
+        long = "dns",
+        help = "nameserver IP to write into the sandboxed resolv.conf (can be specified multiple times); defaults to --gateway when omitted"
+    )]
+    pub dns: Vec<String>,
+
+    #[arg(
+        long = "dns-search",
+        help = "search domain to add to the sandboxed resolv.conf (can be specified multiple times)"
+    )]
+    pub dns_search: Vec<String>,
+
     #[arg(trailing_var_arg = true, help = "command to run")]
     pub command: Vec<String>,
 }
 
+/// An additional routed peer, beyond the primary `--wg-*` peer, configured
+/// via `--peer`. This lets wirecage act as a multi-peer gateway instead of
+/// a single point-to-point tunnel.
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    pub public_key: String,
+    pub endpoint: String,
+    pub allowed_ips: Vec<String>,
+    pub psk_file: Option<String>,
+    pub keepalive: Option<u16>,
+}
+
+fn parse_peer(s: &str) -> Result<PeerConfig, String> {
+    let parts: Vec<&str> = s.split(',').collect();
+    if parts.len() < 3 {
+        return Err(
+            "peer format must be: pubkey,endpoint,allowed_ip[;allowed_ip...][,keepalive=<secs>][,psk=<path>]"
+                .to_string(),
+        );
+    }
+
+    let mut psk_file = None;
+    let mut keepalive = None;
+    for opt in &parts[3..] {
+        if let Some(v) = opt.strip_prefix("keepalive=") {
+            keepalive = Some(v.parse().map_err(|_| format!("invalid keepalive: {v}"))?);
+        } else if let Some(v) = opt.strip_prefix("psk=") {
+            psk_file = Some(v.to_string());
+        } else {
+            return Err(format!("unknown peer option: {opt}"));
+        }
+    }
+
+    Ok(PeerConfig {
+        public_key: parts[0].to_string(),
+        endpoint: parts[1].to_string(),
+        allowed_ips: parts[2].split(';').map(|s| s.to_string()).collect(),
+        psk_file,
+        keepalive,
+    })
+}
+
 impl Args {
     pub fn resolve_target_user(&self) -> Result<(u32, u32)> {
         if let Some(ref username) = self.user {
@@ -3,6 +3,7 @@ mod namespace;
 mod network;
 mod network_new;
 mod overlay;
+mod uapi;
 mod wireguard;
 
 use anyhow::{Context, Result};
@@ -125,9 +126,16 @@ fn stage_two(args: Args) -> Result<()> {
         .trim()
         .to_string();
 
-    // Create channels for communication between host WireGuard and child TUN
+    // One TUN->WG channel per worker in the multi-queue pool, so each
+    // queue's reader (in the child namespace) is paired 1:1 with the
+    // worker (in the host namespace) that encapsulates for it; see
+    // network_new::run_wireguard_host and run_tun_child.
     use tokio::sync::mpsc;
-    let (tun_to_wg_tx, tun_to_wg_rx) = mpsc::channel(100);
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    let (tun_to_wg_txs, tun_to_wg_rxs): (Vec<_>, Vec<_>) =
+        (0..threads).map(|_| mpsc::channel(100)).unzip();
     let (wg_to_tun_tx, wg_to_tun_rx) = mpsc::channel(100);
 
     // Start WireGuard in HOST namespace in a dedicated thread
@@ -145,7 +153,7 @@ fn stage_two(args: Args) -> Result<()> {
 
         runtime.block_on(async move {
             debug!("WireGuard runtime started");
-            if let Err(e) = network_new::run_wireguard_host(&args_wg, &private_key_wg, tun_to_wg_rx, wg_to_tun_tx).await {
+            if let Err(e) = network_new::run_wireguard_host(&args_wg, &private_key_wg, tun_to_wg_rxs, wg_to_tun_tx).await {
                 tracing::error!("WireGuard host error: {}", e);
             }
         });
@@ -158,13 +166,14 @@ fn stage_two(args: Args) -> Result<()> {
     debug!("creating network namespace");
     namespace::setup_network_namespace(&args)?;
 
-    // Create and configure TUN interface in new namespace
-    let tun_device = namespace::setup_network_interface(&args)?;
+    // Create and configure the TUN interface's queues in the new namespace,
+    // one per worker in the host-side WireGuard worker pool.
+    let tun_queues = namespace::setup_multi_queue_interface(&args, threads)?;
 
     // Set up overlay filesystem for /etc
     let _overlay_guard = if !args.no_overlay {
         debug!("overlaying /etc...");
-        Some(overlay::setup_etc_overlay(&args.gateway)?)
+        Some(overlay::setup_etc_overlay(&args.gateway, &args.dns, &args.dns_search)?)
     } else {
         None
     };
@@ -180,7 +189,7 @@ fn stage_two(args: Args) -> Result<()> {
             .unwrap();
 
         runtime.block_on(async move {
-            if let Err(e) = network_new::run_tun_child(&args_tun, tun_to_wg_tx, wg_to_tun_rx, tun_device).await {
+            if let Err(e) = network_new::run_tun_child(&args_tun, tun_to_wg_txs, wg_to_tun_rx, tun_queues).await {
                 tracing::error!("TUN child error: {}", e);
             }
         });
@@ -31,29 +31,86 @@ pub fn setup_network_namespace(_args: &Args) -> Result<()> {
     Ok(())
 }
 
-pub fn setup_network_interface(args: &Args) -> Result<std::sync::Arc<std::sync::Mutex<tun::platform::Device>>> {
-    // Create TUN device
-    debug!("creating and configuring TUN device: {}", args.tun);
-    let mut config = tun::Configuration::default();
-    config
-        .name(&args.tun)
-        .up();
-
-    #[cfg(target_os = "linux")]
-    config.platform(|config| {
-        config.packet_information(false);
-    });
-
-    let tun = tun::create(&config)
-        .context("failed to create TUN device")?;
-
-    // Set up networking using rtnetlink
+/// Linux TUN/TAP ioctl flags and request (see linux/if_tun.h); the `tun`
+/// crate doesn't expose multi-queue, so we open the extra queues ourselves.
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_MULTI_QUEUE: libc::c_short = 0x0100;
+
+#[repr(C)]
+struct IfReq {
+    ifr_name: [libc::c_char; libc::IFNAMSIZ],
+    ifr_flags: libc::c_short,
+}
+
+/// Creates a multi-queue TUN interface and opens all `queues` of its file
+/// descriptors (every queue, including the first, must be opened with
+/// `IFF_MULTI_QUEUE` or the kernel rejects the later ones). Brings the
+/// interface up and addresses it via rtnetlink. Used for every queue count,
+/// including 1, so there's a single TUN-creation path for the TUN->WG
+/// worker pool to fan out over.
+pub fn setup_multi_queue_interface(args: &Args, queues: usize) -> Result<Vec<std::fs::File>> {
+    debug!("creating multi-queue TUN device: {} ({} queues)", args.tun, queues);
+    let files = open_tun_queues(&args.tun, queues)?;
     setup_network_config(args)?;
-    
-    // Return device wrapped in Arc<Mutex> so it can be shared with network stack
-    Ok(std::sync::Arc::new(std::sync::Mutex::new(tun)))
+    Ok(files)
+}
+
+/// Opens `queues` file descriptors for the multi-queue TUN interface
+/// `name`, creating the interface with the first one if it doesn't exist
+/// yet. The kernel load-balances
+/// packets routed to the interface across every open queue, so a pool of
+/// worker tasks can each own one queue and run encapsulation/decapsulation
+/// concurrently instead of serializing on a single TUN reader.
+pub fn open_tun_queues(name: &str, queues: usize) -> Result<Vec<std::fs::File>> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut ifr_name = [0 as libc::c_char; libc::IFNAMSIZ];
+    for (dst, src) in ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    (0..queues)
+        .map(|_| {
+            let fd = unsafe {
+                libc::open(b"/dev/net/tun\0".as_ptr() as *const libc::c_char, libc::O_RDWR)
+            };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error()).context("failed to open /dev/net/tun");
+            }
+
+            let mut ifr = IfReq {
+                ifr_name,
+                ifr_flags: IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE,
+            };
+
+            let ret = unsafe { libc::ioctl(fd, TUNSETIFF, &mut ifr) };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                return Err(err).with_context(|| format!("TUNSETIFF failed for queue on {name}"));
+            }
+
+            Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+        })
+        .collect()
 }
 
+/// Dedicated routing table for the TUN device's default route when a
+/// fwmark is configured. Mirrors wg-quick: the TUN route lives here instead
+/// of in `main` so the "not fwmark" rule below can send everything *except*
+/// the marked WireGuard socket traffic through it, while marked traffic
+/// falls through to `main`'s own default route and actually reaches
+/// wg-endpoint instead of looping back into the tunnel.
+const WIRECAGE_ROUTE_TABLE: u32 = 51820;
+
+/// `FIB_RULE_INVERT` from `linux/fib_rules.h`: flips an `ip rule`'s match so
+/// it selects every packet that does *not* match (here, every packet that
+/// isn't marked with our fwmark), the same flag `ip rule add not fwmark ...`
+/// sets.
+const FIB_RULE_INVERT: u32 = 0x2;
+
 fn setup_network_config(args: &Args) -> Result<()> {
     use futures::stream::TryStreamExt;
     use rtnetlink::new_connection;
@@ -105,26 +162,74 @@ fn setup_network_config(args: &Args) -> Result<()> {
             .await
             .context("failed to add address to TUN device")?;
 
-        // Add default IPv4 route
-        handle
+        // Add default IPv4 route. When a fwmark is configured, this route
+        // goes into the dedicated WIRECAGE_ROUTE_TABLE instead of `main`,
+        // so the fwmark bypass rule below actually has somewhere else to
+        // fall through to.
+        let mut route_request = handle
             .route()
             .add()
             .v4()
             .destination_prefix(std::net::Ipv4Addr::new(0, 0, 0, 0), 0)
-            .output_interface(link_index)
+            .output_interface(link_index);
+        if args.fwmark.is_some() {
+            route_request = route_request.table_id(WIRECAGE_ROUTE_TABLE);
+        }
+        route_request
             .execute()
             .await
             .context("failed to add default IPv4 route")?;
 
         // Try to add default IPv6 route (ignore errors)
-        let _ = handle
+        let mut route_request_v6 = handle
             .route()
             .add()
             .v6()
             .destination_prefix(std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0)
-            .output_interface(link_index)
-            .execute()
-            .await;
+            .output_interface(link_index);
+        if args.fwmark.is_some() {
+            route_request_v6 = route_request_v6.table_id(WIRECAGE_ROUTE_TABLE);
+        }
+        let _ = route_request_v6.execute().await;
+
+        // If a fwmark is configured, the WireGuard socket's own encrypted
+        // packets are marked with it. Add a "not fwmark" rule, wg-quick
+        // style, that routes everything *except* that marked traffic
+        // through WIRECAGE_ROUTE_TABLE (i.e. through the TUN device);
+        // marked packets skip this rule and fall through to `main`'s own
+        // default route, so they actually reach wg-endpoint instead of
+        // looping back into the tunnel.
+        if let Some(fwmark) = args.fwmark {
+            debug!(
+                "adding 'not fwmark {}' ip rules so marked packets bypass the TUN default route",
+                fwmark
+            );
+            let mut rule_request = handle
+                .rule()
+                .add()
+                .v4()
+                .fw_mark(fwmark)
+                .table_id(WIRECAGE_ROUTE_TABLE)
+                .priority(100);
+            rule_request.message_mut().header.flags |= FIB_RULE_INVERT;
+            rule_request
+                .execute()
+                .await
+                .context("failed to add fwmark bypass ip rule")?;
+
+            // The IPv6 default route was moved into WIRECAGE_ROUTE_TABLE
+            // above too, so it needs the same bypass rule or --fwmark
+            // orphans it and breaks all IPv6 egress.
+            let mut rule_request_v6 = handle
+                .rule()
+                .add()
+                .v6()
+                .fw_mark(fwmark)
+                .table_id(WIRECAGE_ROUTE_TABLE)
+                .priority(100);
+            rule_request_v6.message_mut().header.flags |= FIB_RULE_INVERT;
+            let _ = rule_request_v6.execute().await;
+        }
 
         // Find and bring up loopback
         let mut lo_links = handle.link().get().match_name("lo".to_string()).execute();
@@ -1,10 +1,14 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 use tracing::{debug, error};
 
 use crate::args::Args;
-use crate::wireguard::WireGuardTunnel;
+use crate::network::decode_key;
+use crate::uapi;
+use crate::wireguard::{set_so_mark, WireGuardDevice};
 
 /// Packet to send from TUN (in child namespace) to WireGuard (in host namespace)
 type TunToWgPacket = Vec<u8>;
@@ -14,166 +18,156 @@ type WgToTunPacket = Vec<u8>;
 
 /// Run WireGuard in the HOST network namespace
 /// This runs before we create the child network namespace
+///
+/// Builds a [`WireGuardDevice`] rather than a single [`WireGuardTunnel`] so
+/// the primary `--wg-*` peer and any additional `--peer`s actually form the
+/// multi-peer, AllowedIPs-routed mesh they're configured as, instead of
+/// every non-primary peer being silently unreachable.
 pub async fn run_wireguard_host(
     args: &Args,
     private_key: &str,
-    tun_to_wg_rx: mpsc::Receiver<TunToWgPacket>,
+    tun_to_wg_rxs: Vec<mpsc::Receiver<TunToWgPacket>>,
     wg_to_tun_tx: mpsc::Sender<WgToTunPacket>,
 ) -> Result<()> {
     debug!("WireGuard host process starting");
 
-    // Create WireGuard tunnel in host namespace
-    let wg_tunnel = WireGuardTunnel::new_simple(
-        private_key,
-        &args.wg_public_key,
-        &args.wg_endpoint,
-    )
-    .await?;
+    let private_key_bytes = decode_key(private_key).context("invalid private key")?;
 
-    let wg_tunnel_tx = wg_tunnel.clone_tunnel();
-    let wg_socket_tx = wg_tunnel.clone_socket();
-    let wg_endpoint = wg_tunnel.endpoint();
+    let std_socket = std::net::UdpSocket::bind("0.0.0.0:0")
+        .context("failed to bind WireGuard UDP socket")?;
+    std_socket.set_nonblocking(true)?;
+    if let Some(mark) = args.fwmark {
+        use std::os::unix::io::AsRawFd;
+        set_so_mark(std_socket.as_raw_fd(), mark)?;
+        debug!("set SO_MARK={} on WireGuard UDP socket", mark);
+    }
+    let socket = Arc::new(UdpSocket::from_std(std_socket)?);
 
-    let wg_tunnel_rx = wg_tunnel.clone_tunnel();
-    let wg_socket_rx = wg_tunnel.clone_socket();
+    let device = Arc::new(WireGuardDevice::new(private_key_bytes, socket));
 
-    // Task: Forward packets from TUN (via channel) to WireGuard socket
-    let mut tun_to_wg_rx = tun_to_wg_rx;
-    tokio::spawn(async move {
-        debug!("TUN->WG forwarder started (host namespace)");
-        while let Some(packet) = tun_to_wg_rx.recv().await {
-            debug!("TUN->WG: received {} bytes from channel", packet.len());
+    let psk = load_psk(args.preshared_key_file.as_deref()).await?;
+    let primary_endpoint: SocketAddr = args
+        .wg_endpoint
+        .parse()
+        .context("invalid --wg-endpoint")?;
+    device
+        .add_peer(
+            &args.wg_public_key,
+            &["0.0.0.0/0".to_string(), "::/0".to_string()],
+            Some(primary_endpoint),
+            psk.as_deref(),
+            args.persistent_keepalive,
+        )
+        .await
+        .context("failed to configure primary WireGuard peer")?;
+    // Proactively handshake instead of waiting for the first outbound
+    // packet to trigger one lazily.
+    device
+        .connect_peer(&args.wg_public_key)
+        .await
+        .context("failed to initiate handshake with primary peer")?;
 
-            // Retry encapsulation if handshake is in progress
-            let mut retries = 0;
-            loop {
-                let mut tunnel = wg_tunnel_tx.lock().await;
-                let mut out_buf = vec![0u8; packet.len() + 148];
+    for peer in &args.peers {
+        let peer_psk = load_psk(peer.psk_file.as_deref()).await?;
+        let endpoint: SocketAddr = peer.endpoint.parse().context("invalid peer endpoint")?;
+        device
+            .add_peer(
+                &peer.public_key,
+                &peer.allowed_ips,
+                Some(endpoint),
+                peer_psk.as_deref(),
+                peer.keepalive,
+            )
+            .await
+            .with_context(|| format!("failed to configure peer {}", peer.public_key))?;
+        device
+            .connect_peer(&peer.public_key)
+            .await
+            .with_context(|| format!("failed to initiate handshake with peer {}", peer.public_key))?;
+    }
 
-                match tunnel.encapsulate(&packet, &mut out_buf) {
-                    boringtun::noise::TunnResult::WriteToNetwork(data) => {
-                        debug!("TUN->WG: sending {} bytes to WireGuard", data.len());
-                        if let Err(e) = wg_socket_tx.send_to(data, wg_endpoint).await {
-                            error!("TUN->WG: send error: {}", e);
-                        }
-                        break; // Success, move to next packet
-                    }
-                    boringtun::noise::TunnResult::Done => {
-                        debug!("TUN->WG: handshake in progress (retry {})", retries);
-                        retries += 1;
-                        if retries > 20 {
-                            error!("TUN->WG: gave up waiting for handshake");
-                            break;
-                        }
-                        drop(tunnel); // Release lock before sleeping
-                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-                        // Retry
-                    }
-                    boringtun::noise::TunnResult::Err(e) => {
-                        error!("TUN->WG: encapsulation error: {:?}", e);
-                        break;
-                    }
-                    _ => {
-                        break;
-                    }
+    if let Some(ref socket_path) = args.uapi_socket {
+        let device_uapi = Arc::clone(&device);
+        let socket_path = socket_path.clone();
+        tokio::spawn(async move {
+            if let Err(e) = uapi::serve(&socket_path, device_uapi).await {
+                error!("UAPI control socket failed: {}", e);
+            }
+        });
+    }
+
+    // TUN -> WG: one worker per TUN queue (see run_tun_child), each pulling
+    // from its own channel and encapsulating for whichever peer's
+    // allowed-ips owns the packet's destination. Each peer's own
+    // Mutex<Tunn> inside WireGuardDevice (rather than one global lock) is
+    // what actually lets these workers run concurrently: two workers can
+    // encapsulate for two different peers at once, but packets for the
+    // same peer still serialize through that peer's lock, preserving
+    // Tunn's ordering and counter state.
+    //
+    // Deliberate deviation from strict per-peer worker affinity: queues are
+    // handed out by the kernel's own load-balancing over the multi-queue
+    // TUN device (by flow hash), not by peer identity, so a given peer's
+    // packets can land on any worker rather than always the same one. We
+    // rely on each peer's `Mutex<Tunn>` for correctness instead of pinning
+    // a peer to a worker, because a real affinity table would need to be
+    // rebuilt every time `add_peer`/`remove_peer` changes the peer set, and
+    // the kernel already spreads flows across queues well enough in
+    // practice; the per-peer lock's serialization is what actually matters
+    // for correctness, and it holds regardless of which worker reads the
+    // packet off the wire.
+    for mut tun_to_wg_rx in tun_to_wg_rxs {
+        let device_tx = Arc::clone(&device);
+        tokio::spawn(async move {
+            debug!("TUN->WG worker started (host namespace)");
+            while let Some(packet) = tun_to_wg_rx.recv().await {
+                debug!("TUN->WG: received {} bytes from channel", packet.len());
+                if let Err(e) = device_tx.send_packet(&packet).await {
+                    debug!("TUN->WG: {}", e);
                 }
             }
-        }
-        debug!("TUN->WG forwarder ended");
-    });
+            debug!("TUN->WG worker ended");
+        });
+    }
 
-    // Task: Forward packets from WireGuard socket to TUN (via channel)
+    // Task: Forward packets from the WireGuard socket to TUN (via channel),
+    // demuxed to the right peer by WireGuardDevice::receive_packet.
+    let device_rx = Arc::clone(&device);
+    let socket_rx = device.clone_socket();
     let recv_handle = tokio::spawn(async move {
-        let local_addr = wg_socket_rx.local_addr().unwrap();
         debug!(
             "WG->TUN forwarder started (host namespace), listening on {}",
-            local_addr
+            socket_rx.local_addr().unwrap()
         );
         let mut recv_buf = vec![0u8; 2048];
         let mut decap_buf = vec![0u8; 2048];
-        let mut counter = 0u32;
 
         loop {
-            counter += 1;
-            debug!("WG->TUN: calling recv_from (attempt {})...", counter);
-            match tokio::time::timeout(
-                std::time::Duration::from_secs(2),
-                wg_socket_rx.recv_from(&mut recv_buf),
-            )
-            .await
-            {
-                Ok(Ok((n, addr))) => {
-                    debug!("WG->TUN: received {} bytes from {}", n, addr);
-
-                    let mut tunnel = wg_tunnel_rx.lock().await;
-                    match tunnel.decapsulate(None, &recv_buf[..n], &mut decap_buf) {
-                        boringtun::noise::TunnResult::WriteToTunnelV4(data, _)
-                        | boringtun::noise::TunnResult::WriteToTunnelV6(data, _) => {
-                            debug!(
-                                "WG->TUN: decapsulated {} bytes IP packet, sending to channel",
-                                data.len()
-                            );
-                            if let Err(e) = wg_to_tun_tx.send(data.to_vec()).await {
-                                error!("WG->TUN: channel send error: {}", e);
-                                break;
-                            } else {
-                                debug!("WG->TUN: sent to channel successfully");
-                            }
-                        }
-                        boringtun::noise::TunnResult::WriteToNetwork(data) => {
-                            debug!(
-                                "WG->TUN: got WireGuard protocol message, sending back {} bytes",
-                                data.len()
-                            );
-                            if let Err(e) = wg_socket_rx.send_to(data, addr).await {
-                                error!("WG->TUN: failed to send protocol message: {}", e);
-                            }
-                        }
-                        boringtun::noise::TunnResult::Err(e) => {
-                            error!("WG->TUN: decapsulation error: {:?}", e);
-                        }
-                        result => {
-                            debug!("WG->TUN: decapsulation result: {:?}", result);
-                        }
-                    }
-                }
-                Ok(Err(e)) => {
+            let (n, addr) = match socket_rx.recv_from(&mut recv_buf).await {
+                Ok(v) => v,
+                Err(e) => {
                     error!("WG->TUN: recv error: {}", e);
                     break;
                 }
-                Err(_timeout) => {
-                    debug!("WG->TUN: recv timeout (no packet in 2s)");
+            };
+            debug!("WG->TUN: received {} bytes from {}", n, addr);
+
+            match device_rx.receive_packet(&recv_buf[..n], addr, &mut decap_buf).await {
+                Ok(Some(data)) => {
+                    debug!("WG->TUN: decapsulated {} bytes IP packet, sending to channel", data.len());
+                    if wg_to_tun_tx.send(data).await.is_err() {
+                        error!("WG->TUN: channel send error");
+                        break;
+                    }
                 }
+                Ok(None) => {}
+                Err(e) => error!("WG->TUN: decapsulation error: {}", e),
             }
         }
         debug!("WG->TUN forwarder ended");
     });
 
-    // Timer task for WireGuard keepalives
-    let wg_tunnel_timer = wg_tunnel.clone_tunnel();
-    let wg_socket_timer = wg_tunnel.clone_socket();
-    let wg_endpoint_timer = wg_tunnel.endpoint();
-
-    let timer_handle = tokio::spawn(async move {
-        debug!("WireGuard timer started");
-        let mut interval = tokio::time::interval(std::time::Duration::from_millis(250));
-        loop {
-            interval.tick().await;
-            debug!("Timer: tick");
-
-            let mut tunnel = wg_tunnel_timer.lock().await;
-            let mut out_buf = vec![0u8; 148];
-
-            match tunnel.update_timers(&mut out_buf) {
-                boringtun::noise::TunnResult::WriteToNetwork(data) => {
-                    debug!("Timer: sending {} bytes", data.len());
-                    let _ = wg_socket_timer.send_to(data, wg_endpoint_timer).await;
-                }
-                boringtun::noise::TunnResult::Done => {}
-                _ => {}
-            }
-        }
-    });
+    let timer_handle = device.spawn_timers();
 
     // Keep all tasks running
     debug!("WireGuard: waiting for tasks to complete");
@@ -188,74 +182,89 @@ pub async fn run_wireguard_host(
     Ok(())
 }
 
+/// Reads and trims a base64 preshared-key file, the same convention used
+/// for `--wg-private-key-file`.
+async fn load_psk(path: Option<&str>) -> Result<Option<String>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let psk = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read preshared key file {path}"))?;
+    Ok(Some(psk.trim().to_string()))
+}
+
 /// Run TUN device and smoltcp stack in CHILD network namespace
 /// This runs after entering the new network namespace
+///
+/// `tun_queues` holds one fd per TUN->WG worker in `run_wireguard_host`
+/// (see [`crate::namespace::setup_multi_queue_interface`]); `tun_to_wg_txs`
+/// pairs each queue with that worker's channel 1:1, so the kernel's own
+/// flow-hash load-balancing across queues is what actually spreads packets
+/// across workers.
 pub async fn run_tun_child(
     _args: &Args,
-    tun_to_wg_tx: mpsc::Sender<TunToWgPacket>,
+    tun_to_wg_txs: Vec<mpsc::Sender<TunToWgPacket>>,
     wg_to_tun_rx: mpsc::Receiver<WgToTunPacket>,
-    tun_device: Arc<std::sync::Mutex<tun::platform::Device>>,
+    tun_queues: Vec<std::fs::File>,
 ) -> Result<()> {
-    debug!("TUN child process starting (in network namespace)");
+    debug!("TUN child process starting (in network namespace), {} queue(s)", tun_queues.len());
 
-    // Create separate file descriptors for read and write
-    // Sharing a single FD between reader/writer causes blocking issues
-    let (tun_read_fd, tun_write_fd) = {
-        let tun = tun_device.lock().unwrap();
-        use std::os::unix::io::AsRawFd;
-        let fd = tun.as_raw_fd();
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
 
-        // Duplicate FD for writer
+    // Only the first queue is used for writing, so no two tasks write to
+    // the same queue concurrently; it gets its own duplicated fd so the
+    // writer and that queue's reader don't share one descriptor (sharing
+    // causes blocking issues between the two).
+    let tun_write_fd = {
+        let fd = tun_queues.first().context("no TUN queue available")?.as_raw_fd();
         let write_fd = unsafe { libc::dup(fd) };
         if write_fd < 0 {
-            panic!("failed to duplicate TUN fd for writer");
+            anyhow::bail!("failed to duplicate TUN fd for writer");
         }
-
-        // Set non-blocking mode on both
-        unsafe {
-            let flags = libc::fcntl(fd, libc::F_GETFL);
-            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
-            let flags = libc::fcntl(write_fd, libc::F_GETFL);
-            libc::fcntl(write_fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
-        }
-
-        (fd, write_fd)
+        set_nonblocking(write_fd);
+        write_fd
     };
 
-    // Task: Read from TUN, send to WireGuard (using raw FD)
-    tokio::task::spawn_blocking(move || {
-        debug!("TUN reader started (blocking)");
-        let mut buf = vec![0u8; 2048];
-        loop {
-            let n = unsafe {
-                libc::read(
-                    tun_read_fd,
-                    buf.as_mut_ptr() as *mut libc::c_void,
-                    buf.len(),
-                )
-            };
+    // Task per queue: read from TUN, send to this queue's paired WireGuard
+    // worker (using raw FD).
+    for (queue, tun_to_wg_tx) in tun_queues.into_iter().zip(tun_to_wg_txs) {
+        let tun_read_fd = queue.into_raw_fd();
+        set_nonblocking(tun_read_fd);
+        tokio::task::spawn_blocking(move || {
+            debug!("TUN reader started (blocking)");
+            let mut buf = vec![0u8; 2048];
+            loop {
+                let n = unsafe {
+                    libc::read(
+                        tun_read_fd,
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
 
-            if n > 0 {
-                debug!("TUN: read {} bytes", n);
-                let packet = buf[..n as usize].to_vec();
-                if tun_to_wg_tx.blocking_send(packet).is_err() {
-                    error!("TUN: failed to send to channel");
+                if n > 0 {
+                    debug!("TUN: read {} bytes", n);
+                    let packet = buf[..n as usize].to_vec();
+                    if tun_to_wg_tx.blocking_send(packet).is_err() {
+                        error!("TUN: failed to send to channel");
+                        break;
+                    }
+                } else if n == 0 {
+                    debug!("TUN: EOF");
+                    break;
+                } else {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() == std::io::ErrorKind::WouldBlock {
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                        continue;
+                    }
+                    error!("TUN: read error: {}", err);
                     break;
                 }
-            } else if n == 0 {
-                debug!("TUN: EOF");
-                break;
-            } else {
-                let err = std::io::Error::last_os_error();
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
-                    continue;
-                }
-                error!("TUN: read error: {}", err);
-                break;
             }
-        }
-    });
+        });
+    }
 
     // Task: Receive from WireGuard, write to TUN (using raw FD)
     let mut wg_to_tun_rx = wg_to_tun_rx;
@@ -298,3 +307,10 @@ pub async fn run_tun_child(
     tokio::signal::ctrl_c().await?;
     Ok(())
 }
+
+fn set_nonblocking(fd: std::os::unix::io::RawFd) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::{Context, Result};
 use nix::mount::{mount, MsFlags};
 use nix::sched::{unshare, CloneFlags};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use tracing::debug;
 
@@ -8,7 +9,11 @@ pub struct OverlayGuard {
     _tmpdir: tempfile::TempDir,
 }
 
-pub fn setup_etc_overlay(gateway: &str) -> Result<OverlayGuard> {
+pub fn setup_etc_overlay(
+    gateway: &str,
+    nameservers: &[String],
+    search_domains: &[String],
+) -> Result<OverlayGuard> {
     // Check if /etc exists and is a directory
     if !Path::new("/etc").is_dir() {
         anyhow::bail!("/etc is not a directory");
@@ -26,10 +31,12 @@ pub fn setup_etc_overlay(gateway: &str) -> Result<OverlayGuard> {
     std::fs::create_dir_all(&workdir).context("failed to create work directory")?;
     std::fs::create_dir_all(&layerdir).context("failed to create layer directory")?;
 
-    // Create resolv.conf in layer pointing to public DNS (will route via WireGuard)
+    // Create resolv.conf in layer, honoring the tunnel's DNS policy the way
+    // a real `[Interface] DNS =` line would, instead of forcing public DNS
+    // on every sandbox.
     std::fs::write(
         layerdir.join("resolv.conf"),
-        "nameserver 1.1.1.1\nnameserver 8.8.8.8\n",
+        render_resolv_conf(gateway, nameservers, search_domains)?,
     )
     .context("failed to write resolv.conf")?;
 
@@ -67,3 +74,32 @@ pub fn setup_etc_overlay(gateway: &str) -> Result<OverlayGuard> {
 
     Ok(OverlayGuard { _tmpdir: tmpdir })
 }
+
+/// Renders a `resolv.conf` from an explicit `--dns`/`--dns-search` policy,
+/// falling back to the tunnel gateway as the resolver when no nameservers
+/// were given instead of silently substituting a public one.
+fn render_resolv_conf(gateway: &str, nameservers: &[String], search_domains: &[String]) -> Result<String> {
+    let nameservers: Vec<IpAddr> = if nameservers.is_empty() {
+        let gateway = gateway
+            .parse()
+            .with_context(|| format!("--gateway is not a valid IP address: {gateway}"))?;
+        vec![gateway]
+    } else {
+        nameservers
+            .iter()
+            .map(|ns| {
+                ns.parse()
+                    .with_context(|| format!("invalid --dns nameserver address: {ns}"))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    let mut resolv_conf = String::new();
+    for ns in nameservers {
+        resolv_conf.push_str(&format!("nameserver {ns}\n"));
+    }
+    if !search_domains.is_empty() {
+        resolv_conf.push_str(&format!("search {}\n", search_domains.join(" ")));
+    }
+    Ok(resolv_conf)
+}
@@ -24,22 +24,66 @@ pub struct ServerArgs {
 
     #[arg(long = "peer", value_parser = parse_peer, help = "Peer in format: pubkey,ip (can be specified multiple times)")]
     pub peers: Vec<PeerConfig>,
+
+    #[arg(
+        long = "uapi-socket",
+        help = "path to a Unix socket that speaks the WireGuard cross-platform UAPI protocol, for runtime peer management with `wg`/`wg show`"
+    )]
+    pub uapi_socket: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = parse_transport,
+        default_value = "udp",
+        help = "transport to listen on: 'udp' for raw UDP, or 'websocket' to accept WireGuard datagrams framed as binary WebSocket messages over TCP (for networks that block or throttle UDP)"
+    )]
+    pub transport: TransportKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Udp,
+    WebSocket,
+}
+
+fn parse_transport(s: &str) -> Result<TransportKind, String> {
+    match s {
+        "udp" => Ok(TransportKind::Udp),
+        "websocket" => Ok(TransportKind::WebSocket),
+        other => Err(format!("unknown transport: {other} (expected 'udp' or 'websocket')")),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct PeerConfig {
     pub public_key: String,
     pub allowed_ips: Vec<String>,
+    pub psk_file: Option<String>,
+    pub keepalive: Option<u16>,
 }
 
 fn parse_peer(s: &str) -> Result<PeerConfig, String> {
     let parts: Vec<&str> = s.split(',').collect();
-    if parts.len() != 2 {
-        return Err("Peer format must be: pubkey,ip".to_string());
+    if parts.len() < 2 {
+        return Err("Peer format must be: pubkey,ip[,keepalive=<secs>][,psk=<path>]".to_string());
+    }
+
+    let mut psk_file = None;
+    let mut keepalive = None;
+    for opt in &parts[2..] {
+        if let Some(v) = opt.strip_prefix("keepalive=") {
+            keepalive = Some(v.parse().map_err(|_| format!("invalid keepalive: {v}"))?);
+        } else if let Some(v) = opt.strip_prefix("psk=") {
+            psk_file = Some(v.to_string());
+        } else {
+            return Err(format!("unknown peer option: {opt}"));
+        }
     }
 
     Ok(PeerConfig {
         public_key: parts[0].to_string(),
         allowed_ips: vec![parts[1].to_string()],
+        psk_file,
+        keepalive,
     })
 }
@@ -1,38 +1,126 @@
 use boringtun::noise::Tunn;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Instant;
+
+/// A point-in-time snapshot of one peer's configuration and statistics, for
+/// rendering over the UAPI control socket without holding the peer's lock.
+pub struct PeerInfo {
+    pub public_key: [u8; 32],
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<String>,
+    pub last_handshake: Option<Instant>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// A parsed `allowed_ips` entry: a network address plus its prefix length.
+#[derive(Debug, Clone, Copy)]
+enum AllowedIp {
+    V4(Ipv4Addr, u8),
+    V6(Ipv6Addr, u8),
+}
 
 pub struct Peer {
     pub tunnel: Tunn,
     pub allowed_ips: Vec<String>,
     pub endpoint: Option<SocketAddr>,
+    /// The locally-assigned session index this peer last echoed back to us
+    /// as a receiver index, if we've completed a handshake with it. Used to
+    /// evict the old entry from `peers_by_idx` when a session is rekeyed.
+    pub current_index: Option<u32>,
+    pub last_handshake: Option<Instant>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    routes: Vec<AllowedIp>,
 }
 
 impl Peer {
     pub fn new(tunnel: Tunn, allowed_ips: Vec<String>) -> Self {
+        let routes = allowed_ips
+            .iter()
+            .filter_map(|s| parse_allowed_ip(s))
+            .collect();
+
         Self {
             tunnel,
             allowed_ips,
             endpoint: None,
+            current_index: None,
+            last_handshake: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            routes,
         }
     }
 
+    /// Returns the prefix length of the most specific `allowed_ips` entry
+    /// that contains `ip`, if any (longest-prefix-match).
+    pub fn matching_prefix_len(&self, ip: &IpAddr) -> Option<u8> {
+        self.routes
+            .iter()
+            .filter_map(|route| route.contains(ip).then_some(route.prefix_len()))
+            .max()
+    }
+
     pub fn owns_ip(&self, ip: &IpAddr) -> bool {
-        for allowed in &self.allowed_ips {
-            // Parse CIDR notation
-            if let Some((network, prefix)) = allowed.split_once('/') {
-                if let Ok(network_ip) = network.parse::<IpAddr>() {
-                    // Simple check: for /32 or /128, exact match
-                    if (prefix == "32" || prefix == "128") && &network_ip == ip {
-                        return true;
-                    }
-                    // For other prefixes, would need proper CIDR matching
-                    // For now, simple prefix match
-                    if ip.to_string().starts_with(network) {
-                        return true;
-                    }
-                }
+        self.matching_prefix_len(ip).is_some()
+    }
+
+    /// Replaces this peer's allowed-ips, re-parsing the routing entries used
+    /// by `owns_ip`/`matching_prefix_len`. Used by dynamic reconfiguration.
+    pub fn set_allowed_ips(&mut self, allowed_ips: Vec<String>) {
+        self.routes = allowed_ips.iter().filter_map(|s| parse_allowed_ip(s)).collect();
+        self.allowed_ips = allowed_ips;
+    }
+}
+
+fn parse_allowed_ip(s: &str) -> Option<AllowedIp> {
+    let (network, prefix) = s.split_once('/')?;
+    let prefix: u8 = prefix.parse().ok()?;
+
+    match network.parse::<IpAddr>().ok()? {
+        IpAddr::V4(addr) => {
+            if prefix > 32 {
+                return None;
+            }
+            Some(AllowedIp::V4(addr, prefix))
+        }
+        IpAddr::V6(addr) => {
+            if prefix > 128 {
+                return None;
+            }
+            Some(AllowedIp::V6(addr, prefix))
+        }
+    }
+}
+
+impl AllowedIp {
+    fn prefix_len(&self) -> u8 {
+        match self {
+            AllowedIp::V4(_, prefix) => *prefix,
+            AllowedIp::V6(_, prefix) => *prefix,
+        }
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        match (self, ip) {
+            (AllowedIp::V4(network, prefix), IpAddr::V4(ip)) => {
+                let mask: u32 = if *prefix == 0 {
+                    0
+                } else {
+                    u32::MAX << (32 - prefix)
+                };
+                (u32::from(*ip) & mask) == (u32::from(*network) & mask)
+            }
+            (AllowedIp::V6(network, prefix), IpAddr::V6(ip)) => {
+                let mask: u128 = if *prefix == 0 {
+                    0
+                } else {
+                    u128::MAX << (128 - prefix)
+                };
+                (u128::from(*ip) & mask) == (u128::from(*network) & mask)
             }
+            _ => false,
         }
-        false
     }
 }
@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, error};
+
+use crate::server_routing::RoutingTable;
+
+const MAX_PACKET: usize = 65536;
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Sits between the UDP/crypto side and the TUN device, so neither side
+/// touches `tun::AsyncDevice` directly. Decrypted packets are handed to
+/// `deliver` for writing out to TUN; packets read off TUN are tagged with
+/// their destination peer (via the shared routing table) and handed back
+/// out over a channel for the crypto side to encapsulate and send.
+///
+/// Because both sides only ever see channels, the TUN endpoint could later
+/// be swapped for a socket to a separate host without touching the crypto
+/// path.
+pub struct PacketRelayer {
+    to_tun_tx: mpsc::Sender<([u8; 32], Vec<u8>)>,
+}
+
+impl PacketRelayer {
+    /// Spawns the TUN reader/writer tasks and returns a handle for
+    /// delivering decrypted packets, plus the receiving half of the
+    /// tagged-for-encapsulation channel.
+    pub fn spawn(
+        tun_read: tokio::io::ReadHalf<tun::AsyncDevice>,
+        tun_write: tokio::io::WriteHalf<tun::AsyncDevice>,
+        peers_by_ip: Arc<Mutex<RoutingTable>>,
+    ) -> (Self, mpsc::Receiver<([u8; 32], Vec<u8>)>) {
+        let (to_tun_tx, to_tun_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (from_tun_tx, from_tun_rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+        spawn_tun_writer(tun_write, to_tun_rx);
+        spawn_tun_reader(tun_read, peers_by_ip, from_tun_tx);
+
+        (Self { to_tun_tx }, from_tun_rx)
+    }
+
+    /// Queues a decrypted packet to be written out to TUN. `peer_key` is
+    /// currently only used for logging; TUN writes don't need to know which
+    /// peer a packet came from.
+    pub async fn deliver(&self, peer_key: [u8; 32], packet: Vec<u8>) {
+        if self.to_tun_tx.send((peer_key, packet)).await.is_err() {
+            error!("packet relayer: TUN writer task is gone, dropping decrypted packet");
+        }
+    }
+}
+
+fn spawn_tun_writer(
+    mut tun_write: tokio::io::WriteHalf<tun::AsyncDevice>,
+    mut to_tun_rx: mpsc::Receiver<([u8; 32], Vec<u8>)>,
+) {
+    tokio::spawn(async move {
+        while let Some((peer_key, packet)) = to_tun_rx.recv().await {
+            debug!("packet relayer: writing {} bytes from peer {:02x?}... to TUN", packet.len(), &peer_key[..4]);
+            if let Err(e) = tun_write.write_all(&packet).await {
+                error!("packet relayer: failed to write to TUN: {}", e);
+                break;
+            }
+        }
+    });
+}
+
+fn spawn_tun_reader(
+    mut tun_read: tokio::io::ReadHalf<tun::AsyncDevice>,
+    peers_by_ip: Arc<Mutex<RoutingTable>>,
+    from_tun_tx: mpsc::Sender<([u8; 32], Vec<u8>)>,
+) {
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_PACKET];
+        loop {
+            let len = match tun_read.read(&mut buf).await {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("packet relayer: error reading from TUN: {}", e);
+                    break;
+                }
+            };
+
+            let packet = &buf[..len];
+            let Some(dest_ip) = packet_dest(packet) else {
+                continue;
+            };
+
+            let peer_key = { peers_by_ip.lock().await.lookup(&dest_ip) };
+            let Some(peer_key) = peer_key else {
+                debug!("packet relayer: no peer owns destination {}, dropping packet", dest_ip);
+                continue;
+            };
+
+            if from_tun_tx.send((peer_key, packet.to_vec())).await.is_err() {
+                error!("packet relayer: crypto-side receiver is gone, stopping TUN reader");
+                break;
+            }
+        }
+    });
+}
+
+fn packet_dest(packet: &[u8]) -> Option<IpAddr> {
+    if packet.len() < 20 {
+        return None;
+    }
+
+    match packet[0] >> 4 {
+        4 => Some(IpAddr::V4(std::net::Ipv4Addr::new(
+            packet[16], packet[17], packet[18], packet[19],
+        ))),
+        6 if packet.len() >= 40 => {
+            let mut addr = [0u8; 16];
+            addr.copy_from_slice(&packet[24..40]);
+            Some(IpAddr::V6(std::net::Ipv6Addr::from(addr)))
+        }
+        _ => None,
+    }
+}
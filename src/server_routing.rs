@@ -0,0 +1,75 @@
+use std::net::IpAddr;
+
+/// Maps every configured peer's allowed-ips to its public key, and answers
+/// longest-prefix-match lookups for a destination address in O(number of
+/// configured prefixes) rather than the O(peers) `owns_ip` scan it replaces.
+///
+/// This is a flat table sorted by descending prefix length rather than a
+/// real radix trie: with the peer counts wirecage targets, a linear scan
+/// stopping at the first (longest) match is simple and fast enough. Revisit
+/// with a bit-trie if peer counts grow large enough for iteration to matter.
+#[derive(Default)]
+pub struct RoutingTable {
+    v4: Vec<(u32, u8, [u8; 32])>,
+    v6: Vec<(u128, u8, [u8; 32])>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the whole table's contents from scratch. Called once at
+    /// startup, and again whenever the peer set changes at runtime.
+    pub fn rebuild<'a>(&mut self, peers: impl Iterator<Item = (&'a [u8; 32], &'a Vec<String>)>) {
+        self.v4.clear();
+        self.v6.clear();
+
+        for (pub_key, allowed_ips) in peers {
+            for allowed in allowed_ips {
+                self.insert(*pub_key, allowed);
+            }
+        }
+
+        // Longest prefix first, so `lookup`'s first match is the winner.
+        self.v4.sort_by(|a, b| b.1.cmp(&a.1));
+        self.v6.sort_by(|a, b| b.1.cmp(&a.1));
+    }
+
+    fn insert(&mut self, pub_key: [u8; 32], cidr: &str) {
+        let Some((network, prefix)) = cidr.split_once('/') else {
+            return;
+        };
+        let Ok(prefix) = prefix.parse::<u8>() else {
+            return;
+        };
+
+        match network.parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) if prefix <= 32 => self.v4.push((u32::from(addr), prefix, pub_key)),
+            Ok(IpAddr::V6(addr)) if prefix <= 128 => self.v6.push((u128::from(addr), prefix, pub_key)),
+            _ => {}
+        }
+    }
+
+    /// Returns the public key of the peer with the longest allowed-ips
+    /// prefix containing `ip`, if any. `0.0.0.0/0`/`::/0` act as catch-alls
+    /// and only win when nothing more specific matches.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<[u8; 32]> {
+        match ip {
+            IpAddr::V4(addr) => {
+                let addr = u32::from(*addr);
+                self.v4.iter().find_map(|(network, prefix, key)| {
+                    let mask: u32 = if *prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+                    ((addr & mask) == (network & mask)).then_some(*key)
+                })
+            }
+            IpAddr::V6(addr) => {
+                let addr = u128::from(*addr);
+                self.v6.iter().find_map(|(network, prefix, key)| {
+                    let mask: u128 = if *prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+                    ((addr & mask) == (network & mask)).then_some(*key)
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Pluggable datagram transport for the server's encrypted WireGuard packets.
+//
+// By default the server speaks raw UDP, like `wg`/`wireguard-go`. Some
+// networks block or throttle UDP outright, or only allow egress through an
+// HTTP(S) proxy; `--transport websocket` trades that for a WebSocket-over-TCP
+// listener on the same address, framing each encrypted datagram as one
+// binary WebSocket message, so clients can tunnel through port 443 instead.
+// Either way the boringtun noise layer above this is unaffected - it never
+// sees anything but opaque bytes and a `SocketAddr` to correlate them by.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, warn};
+
+const INBOUND_CHANNEL_CAPACITY: usize = 256;
+
+/// A datagram-oriented transport for encrypted WireGuard packets. Mirrors
+/// `UdpSocket::recv_from`/`send_to` so callers don't need to know whether
+/// they're talking to a real UDP socket or a WebSocket connection
+/// underneath.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub async fn bind(addr: &str) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .context("failed to bind UDP socket")?;
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        Ok(self.socket.recv_from(buf).await?)
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        Ok(self.socket.send_to(buf, addr).await?)
+    }
+}
+
+/// Accepts WebSocket connections and fans their binary messages in and out
+/// through a single `recv_from`/`send_to` pair, keyed by each connection's
+/// TCP peer address (there's no real "port" on the other end of a WebSocket
+/// the way there is for UDP, but the peer address alone is enough to
+/// correlate packets with the boringtun session they belong to).
+pub struct WebSocketTransport {
+    inbound_rx: Mutex<mpsc::Receiver<(SocketAddr, Vec<u8>)>>,
+    outbound: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>,
+}
+
+impl WebSocketTransport {
+    pub async fn bind(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context("failed to bind WebSocket transport")?;
+
+        let (inbound_tx, inbound_rx) = mpsc::channel(INBOUND_CHANNEL_CAPACITY);
+        let outbound: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+
+        let outbound_accept = Arc::clone(&outbound);
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("WebSocket transport: accept failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+                    Ok(ws) => ws,
+                    Err(e) => {
+                        warn!("WebSocket transport: handshake with {} failed: {}", peer_addr, e);
+                        continue;
+                    }
+                };
+
+                debug!("WebSocket transport: accepted connection from {}", peer_addr);
+
+                let (send_tx, send_rx) = mpsc::channel(INBOUND_CHANNEL_CAPACITY);
+                outbound_accept.lock().await.insert(peer_addr, send_tx);
+                spawn_connection(
+                    ws_stream,
+                    peer_addr,
+                    send_rx,
+                    inbound_tx.clone(),
+                    Arc::clone(&outbound_accept),
+                );
+            }
+        });
+
+        Ok(Self {
+            inbound_rx: Mutex::new(inbound_rx),
+            outbound,
+        })
+    }
+}
+
+fn spawn_connection(
+    ws_stream: WebSocketStream<TcpStream>,
+    peer_addr: SocketAddr,
+    mut send_rx: mpsc::Receiver<Vec<u8>>,
+    inbound_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    outbound: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>,
+) {
+    tokio::spawn(async move {
+        let (mut write, mut read) = ws_stream.split();
+
+        loop {
+            tokio::select! {
+                msg = read.next() => {
+                    match msg {
+                        Some(Ok(Message::Binary(data))) => {
+                            if inbound_tx.send((peer_addr, data)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => {
+                            warn!("WebSocket transport: read error from {}: {}", peer_addr, e);
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+                data = send_rx.recv() => {
+                    match data {
+                        Some(data) => {
+                            if let Err(e) = write.send(Message::Binary(data)).await {
+                                warn!("WebSocket transport: write error to {}: {}", peer_addr, e);
+                                break;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        outbound.lock().await.remove(&peer_addr);
+        debug!("WebSocket transport: connection from {} closed", peer_addr);
+    });
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (addr, data) = self
+            .inbound_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("WebSocket transport closed")?;
+
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, addr))
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let sender = {
+            let outbound = self.outbound.lock().await;
+            outbound.get(&addr).cloned()
+        };
+
+        let sender = sender.with_context(|| format!("no open WebSocket connection for {addr}"))?;
+        sender
+            .send(buf.to_vec())
+            .await
+            .context("WebSocket connection closed")?;
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,189 @@
+// WireGuard UAPI control socket
+//
+// Implements the same get/set text protocol that the cross-platform
+// userspace WireGuard implementations expose over a Unix socket, so the
+// unmodified `wg`/`wg show` tooling can inspect and reconfigure the
+// client's peers while it runs. See:
+// https://www.wireguard.com/xplatform/#configuration-protocol
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, error, warn};
+
+use crate::wireguard::WireGuardDevice;
+
+pub async fn serve(socket_path: &str, device: Arc<WireGuardDevice>) -> Result<()> {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)
+        .with_context(|| format!("failed to bind UAPI socket at {socket_path}"))?;
+
+    debug!("UAPI control socket listening on {}", socket_path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let device = Arc::clone(&device);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, device).await {
+                warn!("UAPI connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, device: Arc<WireGuardDevice>) -> Result<()> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+
+    // A single request is one or more `key=value` lines terminated by a
+    // blank line.
+    let mut request = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            let response = handle_request(&device, &request).await;
+            write_half.write_all(response.as_bytes()).await?;
+            request.clear();
+            continue;
+        }
+        request.push(line);
+    }
+
+    Ok(())
+}
+
+async fn handle_request(device: &WireGuardDevice, lines: &[String]) -> String {
+    if lines.first().map(String::as_str) == Some("get=1") {
+        return render_get(device).await;
+    }
+
+    match apply_set(device, lines).await {
+        Ok(()) => "errno=0\n\n".to_string(),
+        Err(e) => {
+            error!("UAPI set request failed: {}", e);
+            "errno=1\n\n".to_string()
+        }
+    }
+}
+
+async fn render_get(device: &WireGuardDevice) -> String {
+    let mut out = String::new();
+    for peer in device.peers_snapshot().await {
+        out.push_str(&format!("public_key={}\n", hex_of_base64(&peer.public_key_base64())));
+        if let Some(endpoint) = peer.endpoint().await {
+            out.push_str(&format!("endpoint={endpoint}\n"));
+        }
+        for allowed_ip in peer.allowed_ips().await {
+            out.push_str(&format!("allowed_ip={allowed_ip}\n"));
+        }
+        if let Some(last_handshake) = peer.last_handshake().await {
+            // The protocol wants seconds since the Unix epoch.
+            let epoch_secs = std::time::SystemTime::now()
+                .checked_sub(last_handshake.elapsed())
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+            out.push_str(&format!("last_handshake_time_sec={epoch_secs}\n"));
+        }
+        out.push_str(&format!("rx_bytes={}\n", peer.rx_bytes()));
+        out.push_str(&format!("tx_bytes={}\n", peer.tx_bytes()));
+    }
+    out.push_str("errno=0\n\n");
+    out
+}
+
+/// The UAPI protocol encodes keys as hex; wirecage stores them as the
+/// base64 that the rest of the codebase (and `wg genkey`) already uses.
+/// Round-trip through base64 so `wg show` renders a familiar key.
+fn hex_of_base64(key: &str) -> String {
+    match base64::engine::general_purpose::STANDARD.decode(key.trim()) {
+        Ok(bytes) => bytes.iter().map(|b| format!("{b:02x}")).collect(),
+        Err(_) => key.to_string(),
+    }
+}
+
+fn base64_of_hex(key: &str) -> Option<String> {
+    if key.len() != 64 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(32);
+    for chunk in key.as_bytes().chunks(2) {
+        let byte = u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+        bytes.push(byte);
+    }
+    Some(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+async fn apply_set(device: &WireGuardDevice, lines: &[String]) -> Result<()> {
+    let mut current_public_key: Option<String> = None;
+    let mut current_endpoint: Option<SocketAddr> = None;
+    let mut current_allowed_ips: Vec<String> = Vec::new();
+    let mut current_remove = false;
+
+    for line in lines {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        match key {
+            // Cleared the moment we see it, before any peer in this request
+            // is flushed - applying it after the loop would wipe out peers
+            // this same request just (re-)added.
+            "replace_peers" if value == "true" => device.replace_peers().await,
+            "public_key" => {
+                flush_peer(
+                    device,
+                    current_public_key.take(),
+                    current_endpoint.take(),
+                    std::mem::take(&mut current_allowed_ips),
+                    std::mem::replace(&mut current_remove, false),
+                )
+                .await?;
+                current_public_key = Some(base64_of_hex(value).unwrap_or_else(|| value.to_string()));
+            }
+            "endpoint" => {
+                current_endpoint = value.parse().ok();
+            }
+            "allowed_ip" => {
+                current_allowed_ips.push(value.to_string());
+            }
+            "remove" if value == "true" => {
+                current_remove = true;
+            }
+            // persistent_keepalive_interval= and preshared_key= are accepted
+            // (and ignored) here: a peer's preshared key and keepalive are
+            // only set at startup (see Args::peers / PeerConfig); peers added
+            // through this socket get neither, and existing peers keep theirs.
+            _ => {}
+        }
+    }
+
+    flush_peer(
+        device,
+        current_public_key,
+        current_endpoint,
+        current_allowed_ips,
+        current_remove,
+    )
+    .await
+}
+
+async fn flush_peer(
+    device: &WireGuardDevice,
+    public_key: Option<String>,
+    endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<String>,
+    remove: bool,
+) -> Result<()> {
+    let Some(public_key) = public_key else {
+        return Ok(());
+    };
+
+    if remove {
+        device.remove_peer(&public_key).await?;
+        return Ok(());
+    }
+
+    device.add_peer(&public_key, &allowed_ips, endpoint, None, None).await
+}
@@ -1,16 +1,41 @@
 use anyhow::{Context, Result};
 use base64::Engine;
+use boringtun::noise::handshake::parse_handshake_anon;
+use boringtun::noise::rate_limiter::RateLimiter;
 use boringtun::noise::{Tunn, TunnResult};
-use std::net::SocketAddr;
+use boringtun::x25519::{PublicKey, StaticSecret};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 use tracing::{debug, error};
 
+use crate::network::{decode_key, parse_allowed_ip};
+
+/// Handshake initiations accepted per 250ms timer tick (the limiter's count
+/// is reset on every tick, so 20 per second) before the rate limiter starts
+/// replying with stateless cookies instead of doing the expensive crypto,
+/// matching the limit the reference WireGuard implementations use.
+const HANDSHAKE_RATE_LIMIT: u64 = 5;
+
 pub struct WireGuardTunnel {
     tunnel: Arc<Mutex<Box<Tunn>>>,
     socket: Arc<UdpSocket>,
     endpoint: SocketAddr,
+    /// Guards the handshake path against a spoofed-UDP flood; reset on the
+    /// same tick as the keepalive timer.
+    rate_limiter: Arc<RateLimiter>,
+    /// Drives handshake/rekey/keepalive timers for `tunnel` for as long as
+    /// this tunnel lives; aborted on drop so it doesn't outlive its socket.
+    timer_task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for WireGuardTunnel {
+    fn drop(&mut self) {
+        self.timer_task.abort();
+    }
 }
 
 impl WireGuardTunnel {
@@ -20,42 +45,34 @@ impl WireGuardTunnel {
         endpoint: &str,
         _local_ip: &str,
         socket_fd: std::os::unix::io::RawFd,
+        fwmark: Option<u32>,
+        preshared_key: Option<&str>,
+        persistent_keepalive: Option<u16>,
     ) -> Result<Self> {
-        // Decode keys
-        let private_key_bytes = base64::engine::general_purpose::STANDARD
-            .decode(private_key.trim())
-            .context("invalid private key base64")?;
-
-        let public_key_bytes = base64::engine::general_purpose::STANDARD
-            .decode(public_key.trim())
-            .context("invalid public key base64")?;
-
-        if private_key_bytes.len() != 32 {
-            anyhow::bail!("private key must be 32 bytes");
-        }
-        if public_key_bytes.len() != 32 {
-            anyhow::bail!("public key must be 32 bytes");
-        }
-
-        let mut priv_key = [0u8; 32];
-        priv_key.copy_from_slice(&private_key_bytes);
-
-        let mut pub_key = [0u8; 32];
-        pub_key.copy_from_slice(&public_key_bytes);
+        // Decode keys (same base64, 32-byte validation for all three)
+        let priv_key = decode_key(private_key).context("invalid private key")?;
+        let pub_key = decode_key(public_key).context("invalid public key")?;
+        let psk = preshared_key
+            .map(decode_key)
+            .transpose()
+            .context("invalid preshared key")?;
 
         // Parse endpoint
         let endpoint: SocketAddr = endpoint
             .parse()
             .context("invalid endpoint address")?;
 
+        let own_public = PublicKey::from(&StaticSecret::from(priv_key));
+        let rate_limiter = Arc::new(RateLimiter::new(&own_public, HANDSHAKE_RATE_LIMIT));
+
         // Create tunnel
         let tunnel = Tunn::new(
             priv_key.into(),
             pub_key.into(),
-            None,
-            None,
+            psk,
+            persistent_keepalive,
             0,
-            None,
+            Some(Arc::clone(&rate_limiter)),
         )
         .map_err(|e| anyhow::anyhow!("failed to create WireGuard tunnel: {}", e))?;
 
@@ -66,17 +83,79 @@ impl WireGuardTunnel {
             std_socket.set_nonblocking(true)?;
             UdpSocket::from_std(std_socket)?
         };
-        
+
+        if let Some(mark) = fwmark {
+            set_so_mark(socket_fd, mark)?;
+            debug!("set SO_MARK={} on WireGuard UDP socket", mark);
+        }
+
         let local_addr = socket.local_addr()?;
         debug!("WireGuard tunnel created, local: {}, endpoint: {}", local_addr, endpoint);
 
+        let tunnel = Arc::new(Mutex::new(Box::new(tunnel)));
+        let socket = Arc::new(socket);
+        let timer_task = spawn_timer_task(
+            Arc::clone(&tunnel),
+            Arc::clone(&socket),
+            endpoint,
+            Arc::clone(&rate_limiter),
+        );
+
         Ok(Self {
-            tunnel: Arc::new(Mutex::new(Box::new(tunnel))),
-            socket: Arc::new(socket),
+            tunnel,
+            socket,
             endpoint,
+            rate_limiter,
+            timer_task,
         })
     }
 
+    /// Sends the initial handshake and blocks until the peer responds,
+    /// instead of relying on the first `send_packet`/`receive_packet` call
+    /// to trigger a handshake lazily.
+    pub async fn connect(&self) -> Result<()> {
+        let mut out_buf = vec![0u8; 148];
+        let result = {
+            let mut tunnel = self.tunnel.lock().await;
+            tunnel.format_handshake_initiation(&mut out_buf, false)
+        };
+
+        if let TunnResult::WriteToNetwork(data) = result {
+            self.socket
+                .send_to(data, self.endpoint)
+                .await
+                .context("failed to send handshake initiation")?;
+        }
+
+        let mut recv_buf = vec![0u8; 2048];
+        let mut decap_buf = vec![0u8; 2048];
+        loop {
+            let (len, _addr) = self
+                .socket
+                .recv_from(&mut recv_buf)
+                .await
+                .context("failed to receive handshake response")?;
+
+            let mut tunnel = self.tunnel.lock().await;
+            match tunnel.decapsulate(None, &recv_buf[..len], &mut decap_buf) {
+                TunnResult::Done => return Ok(()),
+                TunnResult::WriteToNetwork(data) => {
+                    let data = data.to_vec();
+                    drop(tunnel);
+                    self.socket
+                        .send_to(&data, self.endpoint)
+                        .await
+                        .context("failed to send handshake follow-up")?;
+                    return Ok(());
+                }
+                TunnResult::Err(e) => {
+                    debug!("handshake decapsulation error while connecting: {:?}", e);
+                }
+                _ => {}
+            }
+        }
+    }
+
     pub async fn send_packet(&self, packet: &[u8]) -> Result<()> {
         let mut tunnel = self.tunnel.lock().await;
         let mut out_buf = vec![0u8; packet.len() + 148]; // WireGuard overhead
@@ -97,17 +176,46 @@ impl WireGuardTunnel {
 
     pub async fn receive_packet(&self, buf: &mut [u8]) -> Result<Option<Vec<u8>>> {
         let mut recv_buf = vec![0u8; 2048];
-        
+
         match tokio::time::timeout(
             std::time::Duration::from_millis(100),
             self.socket.recv_from(&mut recv_buf)
         ).await {
-            Ok(Ok((len, _addr))) => {
+            Ok(Ok((len, addr))) => {
+                // Check the cookie-reply rate limiter before spending any
+                // handshake crypto on this datagram; under load it answers
+                // with a stateless cookie instead of letting us process it.
+                let mut cookie_buf = vec![0u8; 148];
+                let parsed = match self.rate_limiter.verify_packet(
+                    Some(addr.ip()),
+                    &recv_buf[..len],
+                    &mut cookie_buf,
+                ) {
+                    Ok(parsed) => parsed,
+                    Err(TunnResult::WriteToNetwork(cookie)) => {
+                        self.socket
+                            .send_to(cookie, addr)
+                            .await
+                            .context("failed to send cookie reply")?;
+                        return Ok(None);
+                    }
+                    Err(_) => return Ok(None),
+                };
+
                 let mut tunnel = self.tunnel.lock().await;
-                match tunnel.decapsulate(None, &recv_buf[..len], buf) {
+                match tunnel.handle_verified_packet(parsed, buf) {
                     TunnResult::WriteToTunnelV4(data, _) | TunnResult::WriteToTunnelV6(data, _) => {
                         Ok(Some(data.to_vec()))
                     }
+                    TunnResult::WriteToNetwork(data) => {
+                        let data = data.to_vec();
+                        drop(tunnel);
+                        self.socket
+                            .send_to(&data, addr)
+                            .await
+                            .context("failed to send handshake follow-up")?;
+                        Ok(None)
+                    }
                     TunnResult::Err(e) => {
                         debug!("WireGuard decapsulation error: {:?}", e);
                         Ok(None)
@@ -132,3 +240,598 @@ impl WireGuardTunnel {
         self.endpoint
     }
 }
+
+/// Drives `tunnel`'s handshake/rekey/keepalive timers on a 250ms tick for as
+/// long as the returned task isn't aborted. Without this, a tunnel that's
+/// otherwise idle never rekeys once its session hits reject-after-time and
+/// silently stops passing traffic.
+fn spawn_timer_task(
+    tunnel: Arc<Mutex<Box<Tunn>>>,
+    socket: Arc<UdpSocket>,
+    endpoint: SocketAddr,
+    rate_limiter: Arc<RateLimiter>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(250));
+        loop {
+            interval.tick().await;
+
+            // Resets the rate limiter's handshake counter on the same tick
+            // as the keepalive loop, so a burst of spoofed handshakes only
+            // pushes it under load until the next tick.
+            rate_limiter.reset_count();
+
+            let mut out_buf = vec![0u8; 148];
+            let result = {
+                let mut tunnel = tunnel.lock().await;
+                tunnel.update_timers(&mut out_buf)
+            };
+
+            if let TunnResult::WriteToNetwork(data) = result {
+                if let Err(e) = socket.send_to(data, endpoint).await {
+                    error!("failed to send WireGuard timer packet: {}", e);
+                }
+            }
+        }
+    })
+}
+
+/// One peer managed by a [`WireGuardDevice`]: its own noise session, the
+/// allowed-ips it's routed for, and the endpoint its traffic currently goes
+/// to (which may roam as packets arrive from a new source address).
+pub struct DevicePeer {
+    tunnel: Mutex<Box<Tunn>>,
+    public_key: [u8; 32],
+    routes: Mutex<Vec<(IpAddr, u8)>>,
+    endpoint: Mutex<Option<SocketAddr>>,
+    /// The locally-assigned session index this peer last echoed back to us
+    /// as a receiver index, used to evict the old `peers_by_idx` entry when
+    /// a session is rekeyed.
+    current_index: Mutex<Option<u32>>,
+    last_handshake: Mutex<Option<Instant>>,
+    rx_bytes: std::sync::atomic::AtomicU64,
+    tx_bytes: std::sync::atomic::AtomicU64,
+}
+
+impl DevicePeer {
+    async fn matching_prefix_len(&self, ip: &IpAddr) -> Option<u8> {
+        self.routes
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(network, prefix)| device_route_contains(*network, *prefix, ip).then_some(*prefix))
+            .max()
+    }
+
+    pub fn public_key_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.public_key)
+    }
+
+    pub async fn allowed_ips(&self) -> Vec<String> {
+        self.routes
+            .lock()
+            .await
+            .iter()
+            .map(|(network, prefix)| format!("{network}/{prefix}"))
+            .collect()
+    }
+
+    pub async fn endpoint(&self) -> Option<SocketAddr> {
+        *self.endpoint.lock().await
+    }
+
+    pub async fn last_handshake(&self) -> Option<Instant> {
+        *self.last_handshake.lock().await
+    }
+
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx_bytes.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+fn device_route_contains(network: IpAddr, prefix: u8, ip: &IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask: u32 = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            (u32::from(*ip) & mask) == (u32::from(network) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask: u128 = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+            (u128::from(*ip) & mask) == (u128::from(network) & mask)
+        }
+        _ => false,
+    }
+}
+
+/// A multi-peer WireGuard device: unlike [`WireGuardTunnel`], which binds a
+/// single `Tunn` to a single fixed endpoint, a device routes outbound
+/// packets to whichever peer's allowed-ips most specifically contains the
+/// destination (longest-prefix match) and demuxes inbound datagrams by
+/// receiver index, mirroring real WireGuard's AllowedIPs semantics.
+pub struct WireGuardDevice {
+    private_key_bytes: [u8; 32],
+    /// Our own static keypair, kept around (beyond `private_key_bytes`) so
+    /// inbound handshake initiations can be routed to the right peer via
+    /// `parse_handshake_anon` without decrypting with every peer in turn.
+    local_static: StaticSecret,
+    local_public: PublicKey,
+    socket: Arc<UdpSocket>,
+    peers: RwLock<HashMap<[u8; 32], Arc<DevicePeer>>>,
+    /// Maps a locally-assigned session index to its owning peer's public
+    /// key, so inbound session (data/handshake-response) packets can skip
+    /// straight to the right peer instead of trying `decapsulate` against
+    /// every peer in turn.
+    peers_by_idx: Mutex<HashMap<u32, [u8; 32]>>,
+    /// Guards the handshake path against a spoofed-UDP flood; reset on the
+    /// same tick as `spawn_timers`' keepalive loop. Device-wide rather than
+    /// per-peer since a handshake initiation arrives before we know which
+    /// peer it's for.
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl WireGuardDevice {
+    pub fn new(private_key_bytes: [u8; 32], socket: Arc<UdpSocket>) -> Self {
+        let local_static = StaticSecret::from(private_key_bytes);
+        let local_public = PublicKey::from(&local_static);
+        let rate_limiter = Arc::new(RateLimiter::new(&local_public, HANDSHAKE_RATE_LIMIT));
+        Self {
+            private_key_bytes,
+            local_static,
+            local_public,
+            socket,
+            peers: RwLock::new(HashMap::new()),
+            peers_by_idx: Mutex::new(HashMap::new()),
+            rate_limiter,
+        }
+    }
+
+    /// Adds a peer, or reconfigures an existing one's allowed-ips/endpoint in
+    /// place (preserving its session).
+    pub async fn add_peer(
+        &self,
+        public_key: &str,
+        allowed_ips: &[String],
+        endpoint: Option<SocketAddr>,
+        psk: Option<&str>,
+        keepalive: Option<u16>,
+    ) -> Result<()> {
+        let public_key_bytes = decode_key(public_key)?;
+
+        if let Some(existing) = self.peers.read().await.get(&public_key_bytes) {
+            if let Some(endpoint) = endpoint {
+                *existing.endpoint.lock().await = Some(endpoint);
+            }
+            if !allowed_ips.is_empty() {
+                *existing.routes.lock().await = allowed_ips.iter().filter_map(|s| parse_allowed_ip(s)).collect();
+            }
+            return Ok(());
+        }
+
+        let psk_bytes = psk.map(decode_key).transpose().context("invalid preshared key")?;
+        let tunnel = Tunn::new(
+            self.private_key_bytes.into(),
+            public_key_bytes.into(),
+            psk_bytes,
+            keepalive,
+            0,
+            None,
+        )
+        .map_err(|e| anyhow::anyhow!("failed to create WireGuard tunnel for {}: {}", public_key, e))?;
+
+        let routes = allowed_ips.iter().filter_map(|s| parse_allowed_ip(s)).collect();
+        let peer = Arc::new(DevicePeer {
+            tunnel: Mutex::new(Box::new(tunnel)),
+            public_key: public_key_bytes,
+            routes: Mutex::new(routes),
+            endpoint: Mutex::new(endpoint),
+            current_index: Mutex::new(None),
+            last_handshake: Mutex::new(None),
+            rx_bytes: std::sync::atomic::AtomicU64::new(0),
+            tx_bytes: std::sync::atomic::AtomicU64::new(0),
+        });
+
+        self.peers.write().await.insert(public_key_bytes, peer);
+        Ok(())
+    }
+
+    /// Proactively sends a handshake initiation to `public_key`'s peer,
+    /// instead of relying purely on the first outbound packet to trigger one
+    /// lazily (real-world latency-sensitive setups don't want to eat a
+    /// handshake RTT on the first packet they actually care about). Unlike
+    /// [`WireGuardTunnel::connect`], this doesn't block for the response:
+    /// `WireGuardDevice` shares one socket across every peer, so the
+    /// response is picked up by the ordinary `receive_packet` loop like any
+    /// other inbound datagram, the same as a lazily-triggered handshake's
+    /// response would be.
+    pub async fn connect_peer(&self, public_key: &str) -> Result<()> {
+        let public_key_bytes = decode_key(public_key)?;
+        let peer = self
+            .peers
+            .read()
+            .await
+            .get(&public_key_bytes)
+            .cloned()
+            .with_context(|| format!("unknown peer {public_key}"))?;
+        let endpoint = peer
+            .endpoint
+            .lock()
+            .await
+            .context("peer has no known endpoint yet")?;
+
+        let mut out_buf = vec![0u8; 148];
+        let result = {
+            let mut tunnel = peer.tunnel.lock().await;
+            tunnel.format_handshake_initiation(&mut out_buf, false)
+        };
+
+        if let TunnResult::WriteToNetwork(data) = result {
+            if let Some(new_index) = extract_initiator_index(data) {
+                self.register_initiator_index(&peer, new_index).await;
+            }
+            self.socket
+                .send_to(data, endpoint)
+                .await
+                .context("failed to send handshake initiation")?;
+        }
+
+        Ok(())
+    }
+
+    /// Snapshots the current peer list, e.g. for the UAPI `get=1` response.
+    pub async fn peers_snapshot(&self) -> Vec<Arc<DevicePeer>> {
+        self.peers.read().await.values().cloned().collect()
+    }
+
+    /// Drops every configured peer, e.g. to implement the UAPI
+    /// `replace_peers=true` directive.
+    pub async fn replace_peers(&self) {
+        self.peers.write().await.clear();
+        self.peers_by_idx.lock().await.clear();
+    }
+
+    pub fn clone_socket(&self) -> Arc<UdpSocket> {
+        Arc::clone(&self.socket)
+    }
+
+    /// Drives every current (and later-added) peer's handshake/rekey/
+    /// keepalive timers on a 250ms tick for as long as the returned task
+    /// isn't aborted, mirroring `WireGuardTunnel`'s single-peer timer task.
+    pub fn spawn_timers(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let device = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(250));
+            loop {
+                interval.tick().await;
+
+                // Resets the rate limiter's handshake counter on the same
+                // tick as the keepalive loop, so a burst of spoofed
+                // handshakes only pushes it under load until the next tick.
+                device.rate_limiter.reset_count();
+
+                let peers: Vec<Arc<DevicePeer>> = device.peers.read().await.values().cloned().collect();
+                for peer in peers {
+                    let mut out_buf = vec![0u8; 148];
+                    let result = {
+                        let mut tunnel = peer.tunnel.lock().await;
+                        tunnel.update_timers(&mut out_buf)
+                    };
+
+                    if let TunnResult::WriteToNetwork(data) = result {
+                        if let Some(endpoint) = *peer.endpoint.lock().await {
+                            if let Err(e) = device.socket.send_to(data, endpoint).await {
+                                error!("failed to send WireGuard timer packet: {}", e);
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Removes a peer, also evicting its entry from `peers_by_idx` so a
+    /// stray late packet can't be misrouted to whatever peer reuses that
+    /// index next.
+    pub async fn remove_peer(&self, public_key: &str) -> Result<()> {
+        let public_key_bytes = decode_key(public_key)?;
+        let removed = self.peers.write().await.remove(&public_key_bytes);
+        if let Some(peer) = removed {
+            if let Some(idx) = *peer.current_index.lock().await {
+                self.peers_by_idx.lock().await.remove(&idx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypts `packet` for whichever peer's allowed-ips most specifically
+    /// contains its destination address, and sends it to that peer's
+    /// endpoint. Drops (returning an error) packets with no matching route.
+    pub async fn send_packet(&self, packet: &[u8]) -> Result<()> {
+        let dest = device_packet_dest(packet).context("could not parse destination of outbound packet")?;
+
+        let peer = {
+            let peers = self.peers.read().await;
+            let mut best: Option<(u8, Arc<DevicePeer>)> = None;
+            for peer in peers.values() {
+                if let Some(prefix) = peer.matching_prefix_len(&dest).await {
+                    if best.as_ref().map(|(p, _)| prefix > *p).unwrap_or(true) {
+                        best = Some((prefix, Arc::clone(peer)));
+                    }
+                }
+            }
+            best.map(|(_, peer)| peer)
+        };
+
+        let peer = peer.with_context(|| format!("no peer owns destination {dest}"))?;
+        let endpoint = peer.endpoint.lock().await.context("peer has no known endpoint yet")?;
+
+        let mut out_buf = vec![0u8; packet.len() + 148];
+        let mut tunnel = peer.tunnel.lock().await;
+        match tunnel.encapsulate(packet, &mut out_buf) {
+            TunnResult::WriteToNetwork(data) => {
+                peer.tx_bytes.fetch_add(data.len() as u64, std::sync::atomic::Ordering::Relaxed);
+                // `encapsulate` triggers a lazy handshake initiation (rather
+                // than sending `packet` itself) when there's no session yet;
+                // register the index we just assigned ourselves the same
+                // way `connect_peer` does, so the response this initiation
+                // provokes hits the indexed fast path in `receive_packet`.
+                let new_index = extract_initiator_index(data);
+                let data = data.to_vec();
+                drop(tunnel);
+                if let Some(new_index) = new_index {
+                    self.register_initiator_index(&peer, new_index).await;
+                }
+                self.socket
+                    .send_to(&data, endpoint)
+                    .await
+                    .context("failed to send to peer")?;
+                Ok(())
+            }
+            TunnResult::Err(e) => anyhow::bail!("encapsulation error: {:?}", e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records `new_index` as `peer`'s currently-assigned session index,
+    /// evicting whatever index it previously held. Used both when we're the
+    /// handshake responder (`receive_packet`, via our own echoed index) and
+    /// when we're the initiator (`connect_peer`/`send_packet`'s lazy
+    /// handshake trigger, via the index we just picked for ourselves).
+    async fn register_initiator_index(&self, peer: &Arc<DevicePeer>, new_index: u32) {
+        let old_index = peer.current_index.lock().await.replace(new_index);
+        let mut peers_by_idx = self.peers_by_idx.lock().await;
+        if let Some(old) = old_index {
+            peers_by_idx.remove(&old);
+        }
+        peers_by_idx.insert(new_index, peer.public_key);
+    }
+
+    /// Decrypts one inbound datagram, routing it to the right peer's session
+    /// without trying every peer's tunnel: session packets (handshake
+    /// response/cookie-reply/data) carry a receiver index we already know,
+    /// and handshake initiations are routed by recovering the initiator's
+    /// static public key via `parse_handshake_anon` - the same trick
+    /// boringtun's own multi-peer `Device` uses - rather than scanning. A
+    /// datagram whose index or initiator is not recognised falls back to
+    /// trying every peer in turn. Records the peer's endpoint so roaming clients keep working without
+    /// reconfiguration. Returns decrypted plaintext for the TUN device, if
+    /// any.
+    pub async fn receive_packet(
+        &self,
+        datagram: &[u8],
+        addr: SocketAddr,
+        out: &mut [u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let msg_type = datagram.first().copied().unwrap_or(0);
+
+        if msg_type == 1 {
+            // Check the cookie-reply rate limiter before spending any
+            // handshake crypto (or even a full scan over every peer) on this
+            // datagram; under a handshake-initiation flood it answers with a
+            // stateless cookie instead of letting us process it further,
+            // mirroring `WireGuardTunnel::receive_packet`. `decapsulate`
+            // below re-validates the handshake MACs itself, so discarding
+            // `verify_packet`'s parsed result here costs a little redundant
+            // crypto but keeps the peer lookup below untouched.
+            let mut cookie_buf = vec![0u8; 148];
+            match self
+                .rate_limiter
+                .verify_packet(Some(addr.ip()), datagram, &mut cookie_buf)
+            {
+                Ok(_) => {}
+                Err(TunnResult::WriteToNetwork(cookie)) => {
+                    self.socket
+                        .send_to(cookie, addr)
+                        .await
+                        .context("failed to send cookie reply")?;
+                    return Ok(None);
+                }
+                Err(_) => return Ok(None),
+            }
+        }
+
+        // The receiver index sits at a different offset depending on
+        // message type: type 2 (handshake response) carries the *sender's*
+        // index at bytes 4..8 and the receiver index at 8..12, while types 3
+        // (cookie reply) and 4 (data) only carry a receiver index, at 4..8.
+        let indexed_key = if msg_type == 2 && datagram.len() >= 12 {
+            self.peers_by_idx.lock().await.get(&u32::from_le_bytes(datagram[8..12].try_into().unwrap())).copied()
+        } else if matches!(msg_type, 3 | 4) && datagram.len() >= 8 {
+            self.peers_by_idx.lock().await.get(&u32::from_le_bytes(datagram[4..8].try_into().unwrap())).copied()
+        } else if msg_type == 1 {
+            parse_handshake_anon(&self.local_static, &self.local_public, datagram)
+                .ok()
+                .map(|half_handshake| half_handshake.peer_static_public)
+        } else {
+            None
+        };
+
+        let candidates: Vec<Arc<DevicePeer>> = if let Some(key) = indexed_key {
+            self.peers.read().await.get(&key).cloned().into_iter().collect()
+        } else {
+            self.peers.read().await.values().cloned().collect()
+        };
+
+        for peer in &candidates {
+            let mut tunnel = peer.tunnel.lock().await;
+            match tunnel.decapsulate(None, datagram, out) {
+                TunnResult::Done => {
+                    *peer.endpoint.lock().await = Some(addr);
+                    *peer.last_handshake.lock().await = Some(Instant::now());
+                    return Ok(None);
+                }
+                TunnResult::WriteToNetwork(data) => {
+                    *peer.endpoint.lock().await = Some(addr);
+                    let new_index = extract_device_index(data);
+                    let data = data.to_vec();
+                    drop(tunnel);
+
+                    if let Some(new_index) = new_index {
+                        self.register_initiator_index(peer, new_index).await;
+                    }
+
+                    self.socket.send_to(&data, addr).await?;
+                    return Ok(None);
+                }
+                TunnResult::WriteToTunnelV4(data, _) | TunnResult::WriteToTunnelV6(data, _) => {
+                    *peer.endpoint.lock().await = Some(addr);
+                    *peer.last_handshake.lock().await = Some(Instant::now());
+                    peer.rx_bytes.fetch_add(data.len() as u64, std::sync::atomic::Ordering::Relaxed);
+                    return Ok(Some(data.to_vec()));
+                }
+                TunnResult::Err(_) => continue,
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn device_packet_dest(packet: &[u8]) -> Option<IpAddr> {
+    if packet.is_empty() {
+        return None;
+    }
+    match packet[0] >> 4 {
+        4 if packet.len() >= 20 => Some(IpAddr::V4(Ipv4Addr::new(
+            packet[16], packet[17], packet[18], packet[19],
+        ))),
+        6 if packet.len() >= 40 => {
+            let mut addr = [0u8; 16];
+            addr.copy_from_slice(&packet[24..40]);
+            Some(IpAddr::V6(Ipv6Addr::from(addr)))
+        }
+        _ => None,
+    }
+}
+
+/// Parses the locally-assigned session index out of a handshake response we
+/// just generated, if the buffer is long enough to contain one.
+fn extract_device_index(response: &[u8]) -> Option<u32> {
+    (response.first().copied() == Some(2) && response.len() >= 8)
+        .then(|| u32::from_le_bytes(response[4..8].try_into().unwrap()))
+}
+
+/// Parses the locally-assigned sender index out of a handshake initiation we
+/// just generated (type 1: `type, reserved x3, sender_index, ...`), the
+/// initiator-side counterpart to `extract_device_index` (responder-side,
+/// type 2). This is the index a handshake response to it will carry back as
+/// its receiver index, so registering it in `peers_by_idx` is what lets
+/// `receive_packet`'s indexed fast path find a self-initiated session.
+fn extract_initiator_index(initiation: &[u8]) -> Option<u32> {
+    (initiation.first().copied() == Some(1) && initiation.len() >= 8)
+        .then(|| u32::from_le_bytes(initiation[4..8].try_into().unwrap()))
+}
+
+/// Marks packets sent through `fd` with `mark`, so a matching `ip rule`
+/// (installed separately) can route them around the TUN device's default
+/// route instead of looping them back into the tunnel.
+pub(crate) fn set_so_mark(fd: std::os::unix::io::RawFd, mark: u32) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to set SO_MARK on WireGuard socket");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ipv4_packet_to(dest: Ipv4Addr) -> Vec<u8> {
+        let mut packet = vec![0u8; 1200];
+        packet[0] = 0x45; // version 4, IHL 5
+        packet[16..20].copy_from_slice(&dest.octets());
+        packet
+    }
+
+    async fn test_device(peers: u8) -> Arc<WireGuardDevice> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let local_addr = socket.local_addr().unwrap();
+        let device = Arc::new(WireGuardDevice::new([1u8; 32], socket));
+        for id in 0..peers {
+            let public_key = base64::engine::general_purpose::STANDARD.encode([id.wrapping_add(2); 32]);
+            device
+                .add_peer(&public_key, &[format!("10.0.{id}.0/24")], Some(local_addr), None, None)
+                .await
+                .unwrap();
+        }
+        device
+    }
+
+    /// Feeds synthetic packets through many peers' `send_packet` at once.
+    /// Each peer's own `Mutex<Tunn>` (rather than a single shared lock) is
+    /// what actually lets this scale with the TUN->WG worker pool in
+    /// `network_new::run_wireguard_host` - the live caller of this method:
+    /// N peers encapsulating concurrently should take roughly as long as one
+    /// peer's share of the work, not N times that.
+    #[tokio::test]
+    async fn concurrent_peers_scale_device_send_packet() {
+        const PEERS: u8 = 16;
+        const PACKETS_PER_PEER: usize = 200;
+
+        let device = test_device(PEERS).await;
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..PEERS)
+            .map(|id| {
+                let device = Arc::clone(&device);
+                let packet = ipv4_packet_to(Ipv4Addr::new(10, 0, id, 1));
+                tokio::spawn(async move {
+                    for _ in 0..PACKETS_PER_PEER {
+                        device.send_packet(&packet).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        // Guards against a regression back to one lock shared across every
+        // peer, which would make this scale with PEERS instead of staying
+        // flat as peers run concurrently - and, unlike a test against
+        // `encapsulate` directly, it exercises `WireGuardDevice::send_packet`
+        // itself, so it actually guards the live multi-queue worker path.
+        assert!(
+            elapsed < Duration::from_secs(2),
+            "expected {} peers x {} packets to send concurrently through WireGuardDevice::send_packet, took {:?}",
+            PEERS,
+            PACKETS_PER_PEER,
+            elapsed
+        );
+    }
+}